@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::synacor::{ARITY, MNEMONICS};
+
+#[derive(Debug)]
+pub enum AsmError {
+    UnknownMnemonic { line: usize, mnemonic: String },
+    WrongArity { line: usize, mnemonic: String, expected: usize, found: usize },
+    UndefinedLabel { line: usize, label: String },
+    BadOperand { line: usize, operand: String },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmError::UnknownMnemonic { line, mnemonic } => {
+                write!(f, "line {}: unknown mnemonic '{}'", line, mnemonic)
+            }
+            AsmError::WrongArity { line, mnemonic, expected, found } => write!(
+                f,
+                "line {}: '{}' takes {} operand(s), found {}",
+                line, mnemonic, expected, found
+            ),
+            AsmError::UndefinedLabel { line, label } => {
+                write!(f, "line {}: undefined label '{}'", line, label)
+            }
+            AsmError::BadOperand { line, operand } => {
+                write!(f, "line {}: bad operand '{}'", line, operand)
+            }
+        }
+    }
+}
+
+// Two-pass assembler: pass one lays out words and records the label->address
+// map, emitting a placeholder zero (and a patch site) for every label
+// operand, whether or not the label is already known. Pass two resolves
+// those patch sites now that every label has been seen.
+pub fn assemble(src: &str) -> Result<Vec<u16>, AsmError> {
+    let mut out: Vec<u16> = Vec::new();
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut patches: Vec<(usize, String, usize)> = Vec::new();
+
+    for (i, raw) in src.lines().enumerate() {
+        let line = i + 1;
+        let text = strip_comment(raw).trim();
+        if text.is_empty() {
+            continue;
+        }
+
+        if let Some(label) = text.strip_suffix(':') {
+            labels.insert(label.trim().to_string(), out.len() as u16);
+            continue;
+        }
+
+        let tokens = tokenize(text);
+        let mut tokens = tokens.iter().map(String::as_str);
+        let mnemonic = tokens.next().unwrap();
+        let code = MNEMONICS.iter().position(|m| *m == mnemonic).ok_or_else(|| {
+            AsmError::UnknownMnemonic { line, mnemonic: mnemonic.to_string() }
+        })?;
+        let operands: Vec<&str> = tokens.collect();
+        let arity = ARITY[code];
+        if operands.len() != arity {
+            return Err(AsmError::WrongArity {
+                line,
+                mnemonic: mnemonic.to_string(),
+                expected: arity,
+                found: operands.len(),
+            });
+        }
+
+        out.push(code as u16);
+        for operand in operands {
+            match parse_value(operand) {
+                Some(value) => out.push(value),
+                None if is_label_token(operand) => {
+                    patches.push((out.len(), operand.to_string(), line));
+                    out.push(0);
+                }
+                None => {
+                    return Err(AsmError::BadOperand { line, operand: operand.to_string() });
+                }
+            }
+        }
+    }
+
+    for (index, label, line) in patches {
+        let addr = labels
+            .get(&label)
+            .ok_or_else(|| AsmError::UndefinedLabel { line, label: label.clone() })?;
+        out[index] = *addr;
+    }
+
+    Ok(out)
+}
+
+// A literal (decimal or `'c'`), or `r0`..`r7` mapped to 32768 + n.
+fn parse_value(tok: &str) -> Option<u16> {
+    if let Some(rest) = tok.strip_prefix('r') {
+        let n: u16 = rest.parse().ok()?;
+        return if n <= 7 { Some(32768 + n) } else { None };
+    }
+    if tok.len() >= 3 && tok.starts_with('\'') && tok.ends_with('\'') {
+        return Some(tok[1..tok.len() - 1].chars().next()? as u16);
+    }
+    tok.parse().ok()
+}
+
+// A token that isn't a number, a register, or a char literal and starts with
+// a letter or `_` is a label reference, resolved (or rejected) in pass two;
+// any other token is a bad operand.
+fn is_label_token(tok: &str) -> bool {
+    tok.chars().next().map_or(false, |c| c.is_alphabetic() || c == '_')
+}
+
+// Like `line.find(';')`, but ignores a `;` inside a `'x'` char literal so
+// e.g. `out ';'` isn't truncated mid-operand.
+fn strip_comment(line: &str) -> &str {
+    let mut in_literal = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\'' => in_literal = !in_literal,
+            ';' if !in_literal => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// Splits a line into whitespace-separated tokens, keeping a `'x'` char
+// literal (which may itself contain whitespace, e.g. `out ' '`) as one
+// token instead of tearing it apart.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        if c == '\'' {
+            let mut tok = String::new();
+            tok.push(chars.next().unwrap());
+            if let Some(ch) = chars.next() {
+                tok.push(ch);
+            }
+            if let Some(&'\'') = chars.peek() {
+                tok.push(chars.next().unwrap());
+            }
+            tokens.push(tok);
+            continue;
+        }
+
+        let mut tok = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            tok.push(c);
+            chars.next();
+        }
+        tokens.push(tok);
+    }
+
+    tokens
+}
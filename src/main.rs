@@ -1,11 +1,76 @@
+use byteorder::{LittleEndian, WriteBytesExt};
 use std::env;
+use std::fs::{self, File};
 
+mod asm;
 mod synacor;
 use self::synacor::Vm;
 
 fn main() {
     let args: Vec<_> = env::args().collect();
-    let mut prog = Vm::new(args.get(1).map_or("challenge.bin", |s| &s));
+
+    if args.iter().any(|a| a == "--asm") {
+        return assemble_cmd(&args);
+    }
+
+    let disasm = args.iter().any(|a| a == "--disasm");
+    let debug = args.iter().any(|a| a == "--debug");
+    let profile = args.iter().any(|a| a == "--profile");
+
+    // `--max-cycles <N>` takes its value as the following argument, so that
+    // slot is excluded from the filename search below.
+    let max_cycles_idx = args.iter().position(|a| a == "--max-cycles");
+    let max_cycles = max_cycles_idx
+        .and_then(|i| args.get(i + 1))
+        .and_then(|s| s.parse::<u64>().ok());
+
+    let filename = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|&(i, a)| !a.starts_with("--") && Some(i) != max_cycles_idx.map(|idx| idx + 1))
+        .map_or("challenge.bin", |(_, s)| s);
+    let mut prog = Vm::new(filename);
+
+    if let Some(max_cycles) = max_cycles {
+        prog.set_max_cycles(max_cycles);
+    }
+
+    if disasm {
+        print!("{}", prog.disassemble(0, usize::MAX));
+        return;
+    }
+
+    if debug {
+        prog.debug();
+        return;
+    }
 
     prog.run();
+
+    if profile {
+        prog.print_profile();
+    }
+}
+
+// `synacor --asm prog.asm prog.bin`: assemble a text program into a
+// little-endian `u16` stream that `Vm::new` can load.
+fn assemble_cmd(args: &[String]) {
+    let positional: Vec<_> = args.iter().skip(1).filter(|a| !a.starts_with("--")).collect();
+    let src_path = positional.get(0).expect("usage: synacor --asm <src.asm> <out.bin>");
+    let out_path = positional.get(1).expect("usage: synacor --asm <src.asm> <out.bin>");
+
+    let src = fs::read_to_string(src_path).unwrap();
+    let words = match asm::assemble(&src) {
+        Ok(words) => words,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut f = File::create(out_path).unwrap();
+    for w in words {
+        f.write_u16::<LittleEndian>(w).unwrap();
+    }
 }
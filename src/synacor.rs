@@ -1,6 +1,7 @@
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Read, Write};
 
 /*
  * Public struct and impl.
@@ -11,6 +12,9 @@ pub struct Vm {
     regs: Vec<u16>,
     stack: Vec<u16>,
     input: Vec<u8>,
+    cycles: u64,
+    max_cycles: Option<u64>,
+    histogram: [u64; 22],
 }
 
 impl Vm {
@@ -29,15 +33,174 @@ impl Vm {
             regs: vec![0; 8],
             stack: Vec::new(),
             input: Vec::new(),
+            cycles: 0,
+            max_cycles: None,
+            histogram: [0; 22],
+        }
+    }
+
+    // Caps the number of instructions `run`/`debug` will execute before
+    // halting with `State::BudgetExceeded`, to catch runaway (e.g.
+    // self-modifying) loops instead of spinning forever.
+    pub fn set_max_cycles(&mut self, max_cycles: u64) {
+        self.max_cycles = Some(max_cycles);
+    }
+
+    // Prints the instruction count and a per-opcode frequency histogram
+    // accumulated over the VM's lifetime.
+    pub fn print_profile(&self) {
+        println!("cycles: {}", self.cycles);
+        for (code, count) in self.histogram.iter().enumerate() {
+            if *count > 0 {
+                println!("{:>10} {}", count, MNEMONICS[code]);
+            }
+        }
+    }
+
+    // Serializes the full execution state (`pc`, `regs`, `stack`, the
+    // possibly self-modified `bin`, any pending `input`, and the cycle
+    // counter/budget/histogram) so a player can checkpoint at the
+    // text-adventure prompt and resume later, e.g. while brute-forcing a
+    // puzzle that requires restoring a known state.
+    pub fn save(&self, path: &str) {
+        let mut f = File::create(path).unwrap();
+        f.write_u16::<LittleEndian>(self.pc as u16).unwrap();
+        write_words(&mut f, &self.regs);
+        write_words(&mut f, &self.stack);
+        write_words(&mut f, &self.bin);
+        write_bytes(&mut f, &self.input);
+        f.write_u64::<LittleEndian>(self.cycles).unwrap();
+        match self.max_cycles {
+            Some(max) => {
+                f.write_u8(1).unwrap();
+                f.write_u64::<LittleEndian>(max).unwrap();
+            }
+            None => f.write_u8(0).unwrap(),
+        }
+        for count in &self.histogram {
+            f.write_u64::<LittleEndian>(*count).unwrap();
+        }
+    }
+
+    pub fn load(path: &str) -> Vm {
+        let mut f = File::open(path).unwrap();
+        let pc = f.read_u16::<LittleEndian>().unwrap() as usize;
+        let regs = read_words(&mut f);
+        let stack = read_words(&mut f);
+        let bin = read_words(&mut f);
+        let input = read_bytes(&mut f);
+        let cycles = f.read_u64::<LittleEndian>().unwrap();
+        let max_cycles = match f.read_u8().unwrap() {
+            1 => Some(f.read_u64::<LittleEndian>().unwrap()),
+            _ => None,
+        };
+        let mut histogram = [0u64; 22];
+        for count in histogram.iter_mut() {
+            *count = f.read_u64::<LittleEndian>().unwrap();
+        }
+
+        Vm {
+            pc,
+            bin,
+            regs,
+            stack,
+            input,
+            cycles,
+            max_cycles,
+            histogram,
         }
     }
 
     pub fn run(&mut self) {
         let mut state = State::Running;
         while let State::Running = state {
-            let op = self.next_op();
-            state = self.run_op(op);
+            // A trap leaves pc on the faulting instruction, not past it.
+            let pc = self.pc;
+            state = self
+                .next_op()
+                .and_then(|op| self.run_op(op))
+                .unwrap_or_else(|trap| {
+                    self.pc = pc;
+                    State::Trapped(trap)
+                });
+        }
+        self.report_halt(&state);
+    }
+
+    // A minimal CPU-monitor-style debugger layered on top of `run`: before
+    // each `next_op` it checks `pc` against a breakpoint set and, when hit
+    // (or while single-stepping), drops into a REPL. Decode/execute stays
+    // untouched underneath -- it still goes through `next_op`/`run_op`.
+    pub fn debug(&mut self) {
+        let mut breakpoints: HashSet<usize> = HashSet::new();
+        let mut stepping = true;
+        let mut state = State::Running;
+
+        while let State::Running = state {
+            if stepping || breakpoints.contains(&self.pc) {
+                if !self.debug_repl(&mut breakpoints, &mut stepping) {
+                    return;
+                }
+            }
+
+            let pc = self.pc;
+            state = self
+                .next_op()
+                .and_then(|op| self.run_op(op))
+                .unwrap_or_else(|trap| {
+                    self.pc = pc;
+                    State::Trapped(trap)
+                });
+        }
+
+        self.report_halt(&state);
+    }
+
+    // Linear sweep disassembly of `bin[start..end]`. Never panics: an
+    // address that isn't a valid opcode (or doesn't have room for its
+    // operands) is emitted as a `.word` directive and the cursor only
+    // advances by one.
+    pub fn disassemble(&self, start: usize, end: usize) -> String {
+        let end = end.min(self.bin.len());
+        let mut out = String::new();
+        let mut addr = start;
+
+        while addr < end {
+            let code = self.bin[addr] as usize;
+            let arity = ARITY.get(code).copied();
+
+            match arity {
+                Some(arity) if addr + arity < self.bin.len() => {
+                    let args: Vec<u16> =
+                        (1..=arity).map(|i| self.bin[addr + i]).collect();
+                    out.push_str(&format!(
+                        "{addr:5} (0x{addr:04x}): {}",
+                        MNEMONICS[code]
+                    ));
+                    for a in &args {
+                        out.push_str(&format!(" {}", fmt_operand(*a)));
+                    }
+                    if code == 19 {
+                        if let Some(&a) = args.first() {
+                            if (0x20..=0x7e).contains(&a) {
+                                out.push_str(&format!(" ; '{}'", a as u8 as char));
+                            }
+                        }
+                    }
+                    out.push('\n');
+                    addr += 1 + arity;
+                }
+                _ => {
+                    out.push_str(&format!(
+                        "{addr:5} (0x{addr:04x}): .word {}\n",
+                        self.bin[addr]
+                    ));
+                    addr += 1;
+                }
+            }
         }
+
+        out
     }
 }
 
@@ -48,6 +211,15 @@ impl Vm {
 enum State {
     Running,
     Halted,
+    Trapped(Trap),
+    BudgetExceeded,
+}
+
+pub(crate) enum Trap {
+    InvalidOpcode(u16),
+    MemoryFault { addr: usize, op: &'static str },
+    StackUnderflow,
+    InvalidValue(u16),
 }
 
 #[derive(Debug)]
@@ -76,18 +248,107 @@ enum Op {
     Noop,               // 21: No operation
 }
 
+// The opcode number for an already-decoded `Op`, i.e. the inverse of the
+// `match` in `next_op`. Used to index the profiling histogram (whose slots
+// line up with `ARITY`/`MNEMONICS`) by the executed instruction.
+fn opcode_index(op: &Op) -> usize {
+    match op {
+        Op::Hlt => 0,
+        Op::Set(..) => 1,
+        Op::Push(..) => 2,
+        Op::Pop(..) => 3,
+        Op::Eq(..) => 4,
+        Op::Gt(..) => 5,
+        Op::Jmp(..) => 6,
+        Op::Jt(..) => 7,
+        Op::Jf(..) => 8,
+        Op::Add(..) => 9,
+        Op::Mul(..) => 10,
+        Op::Mod(..) => 11,
+        Op::And(..) => 12,
+        Op::Or(..) => 13,
+        Op::Not(..) => 14,
+        Op::Rmem(..) => 15,
+        Op::Wmem(..) => 16,
+        Op::Call(..) => 17,
+        Op::Ret => 18,
+        Op::Out(..) => 19,
+        Op::In(..) => 20,
+        Op::Noop => 21,
+    }
+}
+
 // TODO: Abstract better than this.
-static ARITY: [usize; 22] = [
+pub(crate) static ARITY: [usize; 22] = [
     0, 2, 1, 1, 3, 3, 1, 2, 2, 3, 3, 3, 3, 3, 2, 2, 2, 1, 0, 1, 1, 0,
 ];
 
+pub(crate) static MNEMONICS: [&str; 22] = [
+    "hlt", "set", "push", "pop", "eq", "gt", "jmp", "jt", "jf", "add", "mul", "mod", "and", "or",
+    "not", "rmem", "wmem", "call", "ret", "out", "in", "noop",
+];
+
+// Save-file helpers: a u32 element count followed by the elements
+// themselves, little-endian. No serde dependency needed for a handful of
+// fields.
+fn write_words(f: &mut File, words: &[u16]) {
+    f.write_u32::<LittleEndian>(words.len() as u32).unwrap();
+    for &w in words {
+        f.write_u16::<LittleEndian>(w).unwrap();
+    }
+}
+
+fn read_words(f: &mut File) -> Vec<u16> {
+    let len = f.read_u32::<LittleEndian>().unwrap() as usize;
+    let mut words = vec![0; len];
+    f.read_u16_into::<LittleEndian>(&mut words).unwrap();
+    words
+}
+
+fn write_bytes(f: &mut File, bytes: &[u8]) {
+    f.write_u32::<LittleEndian>(bytes.len() as u32).unwrap();
+    f.write_all(bytes).unwrap();
+}
+
+fn read_bytes(f: &mut File) -> Vec<u8> {
+    let len = f.read_u32::<LittleEndian>().unwrap() as usize;
+    let mut bytes = vec![0; len];
+    f.read_exact(&mut bytes).unwrap();
+    bytes
+}
+
+// Renders an operand the same way `v` distinguishes them: 0..=32767 is a
+// literal, 32768..=32775 is a register. Doesn't resolve register contents,
+// since that's only known at runtime.
+fn fmt_operand(x: u16) -> String {
+    match x {
+        0..=32767 => x.to_string(),
+        32768..=32775 => format!("r{}", x - 32768),
+        _ => format!("<invalid:{}>", x),
+    }
+}
+
 impl Vm {
-    fn next_op(&mut self) -> Op {
+    fn next_op(&mut self) -> Result<Op, Trap> {
         let i = self.pc;
         let b = &self.bin;
-        self.pc += 1 + ARITY[b[i] as usize];
 
-        match b[i] {
+        if i >= b.len() {
+            return Err(Trap::MemoryFault { addr: i, op: "fetch" });
+        }
+
+        let code = b[i];
+        let arity = match ARITY.get(code as usize) {
+            Some(&arity) => arity,
+            None => return Err(Trap::InvalidOpcode(code)),
+        };
+        if i + arity >= b.len() {
+            return Err(Trap::MemoryFault { addr: i, op: "fetch" });
+        }
+
+        self.pc += 1 + arity;
+
+        Ok(match code {
             0 => Op::Hlt,
             1 => Op::Set(b[i + 1], b[i + 2]),
             2 => Op::Push(b[i + 1]),
@@ -110,109 +371,312 @@ impl Vm {
             19 => Op::Out(b[i + 1]),
             20 => Op::In(b[i + 1]),
             21 => Op::Noop,
-            code => {
-                panic!("unknown opcode {:?}", code);
+            _ => unreachable!("code {} passed arity lookup", code),
+        })
+    }
+
+    // Destination register operands don't go through `v()` (that's only for
+    // values being read), so they need their own bounds check here instead
+    // of indexing `regs` directly.
+    fn set(&mut self, reg: u16, val: u16) -> Result<(), Trap> {
+        match reg {
+            32768..=32775 => {
+                self.regs[reg as usize - 32768] = val % 32768;
+                Ok(())
+            }
+            _ => Err(Trap::InvalidValue(reg)),
+        }
+    }
+
+    fn report_halt(&self, state: &State) {
+        match state {
+            State::Trapped(trap) => {
+                let reason = match trap {
+                    Trap::InvalidOpcode(code) => format!("invalid opcode {}", code),
+                    Trap::MemoryFault { addr, op } => {
+                        format!("memory fault: {} at 0x{:04x}", op, addr)
+                    }
+                    Trap::StackUnderflow => "stack underflow".to_string(),
+                    Trap::InvalidValue(val) => format!("invalid value {}", val),
+                };
+                eprintln!("trapped at pc=0x{:04x}: {}", self.pc, reason);
+            }
+            State::BudgetExceeded => {
+                eprintln!("cycle budget exceeded after {} cycles", self.cycles)
+            }
+            State::Running | State::Halted => {}
+        }
+    }
+
+    // Runs the debugger REPL until the user asks to step or continue.
+    // Returns false on EOF (stdin closed), meaning the caller should stop.
+    fn debug_repl(&mut self, breakpoints: &mut HashSet<usize>, stepping: &mut bool) -> bool {
+        loop {
+            print!("0x{:04x}> ", self.pc);
+            io::stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if io::stdin().read_line(&mut line).unwrap() == 0 {
+                return false;
+            }
+
+            let mut parts = line.trim().split_whitespace();
+            match parts.next() {
+                Some("s") => {
+                    *stepping = true;
+                    return true;
+                }
+                Some("c") => {
+                    *stepping = false;
+                    return true;
+                }
+                Some("b") => match parts.next().and_then(|s| s.parse().ok()) {
+                    Some(addr) => {
+                        breakpoints.insert(addr);
+                    }
+                    None => println!("usage: b <addr>"),
+                },
+                Some("d") => match parts.next().and_then(|s| s.parse().ok()) {
+                    Some(addr) => {
+                        breakpoints.remove(&addr);
+                    }
+                    None => println!("usage: d <addr>"),
+                },
+                Some("r") => self.print_registers(),
+                Some("m") => {
+                    let addr = parts.next().and_then(|s| s.parse().ok()).unwrap_or(self.pc);
+                    let len = parts.next().and_then(|s| s.parse().ok()).unwrap_or(8);
+                    self.dump_memory(addr, len);
+                }
+                Some("dis") => {
+                    let addr = parts.next().and_then(|s| s.parse().ok()).unwrap_or(self.pc);
+                    let n = parts.next().and_then(|s| s.parse().ok()).unwrap_or(8);
+                    print!("{}", self.disassemble(addr, addr.saturating_add(n)));
+                }
+                _ => println!("commands: s, c, b <addr>, d <addr>, r, m <addr> <len>, dis <addr> [n]"),
             }
         }
     }
 
-    fn set(&mut self, reg: u16, val: u16) {
-        self.regs[reg as usize % 32768] = val % 32768;
+    fn print_registers(&self) {
+        for (i, r) in self.regs.iter().enumerate() {
+            print!("r{}={:<5} ", i, r);
+        }
+        println!();
+        println!("stack: {:?}", self.stack);
     }
 
-    fn run_op(&mut self, op: Op) -> State {
-        let v = |x: u16| match x {
-            0...32767 => x,
-            32768...32775 => self.regs[x as usize % 32768],
-            _ => panic!("invalid number {}", x),
+    fn dump_memory(&self, addr: usize, len: usize) {
+        for i in 0..len {
+            let a = addr + i;
+            if a >= self.bin.len() {
+                break;
+            }
+            print!("{:04x}: {:04x}  ", a, self.bin[a]);
+            if (i + 1) % 4 == 0 {
+                println!();
+            }
+        }
+        println!();
+    }
+
+    fn run_op(&mut self, op: Op) -> Result<State, Trap> {
+        if let Some(max_cycles) = self.max_cycles {
+            if self.cycles >= max_cycles {
+                return Ok(State::BudgetExceeded);
+            }
+        }
+        self.cycles += 1;
+        self.histogram[opcode_index(&op)] += 1;
+
+        let v = |x: u16| -> Result<u16, Trap> {
+            match x {
+                0..=32767 => Ok(x),
+                32768..=32775 => Ok(self.regs[x as usize % 32768]),
+                _ => Err(Trap::InvalidValue(x)),
+            }
         };
         let int = |b: bool| if b { 1 } else { 0 };
 
         match op {
             Op::Hlt => {
-                return State::Halted;
+                return Ok(State::Halted);
             }
             Op::Set(a, b) => {
-                self.set(a, v(b));
+                self.set(a, v(b)?)?;
             }
             Op::Push(a) => {
-                self.stack.push(v(a));
+                self.stack.push(v(a)?);
             }
             Op::Pop(a) => {
-                let pop = self.stack.pop();
-                self.set(a, pop.unwrap());
+                let pop = self.stack.pop().ok_or(Trap::StackUnderflow)?;
+                self.set(a, pop)?;
             }
             Op::Eq(a, b, c) => {
-                self.set(a, int(v(b) == v(c)));
+                self.set(a, int(v(b)? == v(c)?))?;
             }
             Op::Gt(a, b, c) => {
-                self.set(a, int(v(b) > v(c)));
+                self.set(a, int(v(b)? > v(c)?))?;
             }
             Op::Jmp(a) => {
-                self.pc = v(a) as usize;
+                self.pc = v(a)? as usize;
             }
             Op::Jt(a, b) => {
-                if v(a) != 0 {
-                    self.pc = v(b) as usize
+                if v(a)? != 0 {
+                    self.pc = v(b)? as usize
                 }
             }
             Op::Jf(a, b) => {
-                if v(a) == 0 {
-                    self.pc = v(b) as usize
+                if v(a)? == 0 {
+                    self.pc = v(b)? as usize
                 }
             }
             Op::Add(a, b, c) => {
-                self.set(a, v(b) + v(c));
+                self.set(a, v(b)? + v(c)?)?;
             }
             Op::Mul(a, b, c) => {
-                self.set(a, v(b) * v(c));
+                // Widen before multiplying: two 15-bit values can overflow u16.
+                let product = v(b)? as u32 * v(c)? as u32;
+                self.set(a, (product % 32768) as u16)?;
             }
             Op::Mod(a, b, c) => {
-                self.set(a, v(b) % v(c));
+                let (b, c) = (v(b)?, v(c)?);
+                if c == 0 {
+                    return Err(Trap::InvalidValue(c));
+                }
+                self.set(a, b % c)?;
             }
             Op::And(a, b, c) => {
-                self.set(a, v(b) & v(c));
+                self.set(a, v(b)? & v(c)?)?;
             }
             Op::Or(a, b, c) => {
-                self.set(a, v(b) | v(c));
+                self.set(a, v(b)? | v(c)?)?;
             }
             Op::Not(a, b) => {
-                self.set(a, !v(b));
+                self.set(a, !v(b)?)?;
             }
             Op::Rmem(a, b) => {
-                self.set(a, self.bin[v(b) as usize]);
+                let addr = v(b)? as usize;
+                let val = *self
+                    .bin
+                    .get(addr)
+                    .ok_or(Trap::MemoryFault { addr, op: "rmem" })?;
+                self.set(a, val)?;
             }
             Op::Wmem(a, b) => {
-                let a = v(a);
-                let b = v(b);
-                self.bin[a as usize] = b;
+                let addr = v(a)? as usize;
+                let val = v(b)?;
+                match self.bin.get_mut(addr) {
+                    Some(slot) => *slot = val,
+                    None => return Err(Trap::MemoryFault { addr, op: "wmem" }),
+                }
             }
             Op::Call(a) => {
-                let a = v(a) as usize;
+                let a = v(a)? as usize;
                 self.stack.push(self.pc as u16);
                 self.pc = a;
             }
             Op::Ret => match self.stack.pop() {
-                None => return State::Halted,
+                None => return Ok(State::Halted),
                 Some(addr) => self.pc = addr as usize,
             },
             Op::Out(a) => {
                 print!("{}", a as u8 as char);
             }
             Op::In(a) => {
-                if self.input.is_empty() {
-                    let stdin = io::stdin();
-                    let mut handle = stdin.lock();
-                    handle.read_until('\n' as u8, &mut self.input).unwrap();
-                    self.input.reverse();
+                let stdin = io::stdin();
+                let mut handle = stdin.lock();
+                return self.handle_in(a, &mut handle);
+            }
+            Op::Noop => {}
+        };
+        Ok(State::Running)
+    }
+
+    // Blocks on `reader` for the next line, handling `save <path>`/`load
+    // <path>` meta-commands before treating anything else as program input
+    // for register `a`. Generic over the reader (rather than reading
+    // `io::stdin()` directly) so the save/load round trip can be driven
+    // with an in-memory buffer in tests.
+    fn handle_in<R: BufRead>(&mut self, a: u16, reader: &mut R) -> Result<State, Trap> {
+        loop {
+            if self.input.is_empty() {
+                let mut line = Vec::new();
+                reader.read_until(b'\n', &mut line).unwrap();
+
+                let text = String::from_utf8_lossy(&line);
+                let text = text.trim();
+                if let Some(path) = text.strip_prefix("save ") {
+                    self.save(path.trim());
+                    eprintln!("saved state to {}", path.trim());
+                    continue;
                 }
-                match self.input.pop() {
-                    Some(c) => self.set(a, c as u16),
-                    None => return State::Halted,
+                if let Some(path) = text.strip_prefix("load ") {
+                    // The restored Vm is still blocked on this same `in`, so
+                    // loop back around and keep reading for it rather than
+                    // returning — otherwise the register it was waiting on
+                    // is never filled in.
+                    *self = Vm::load(path.trim());
+                    eprintln!("loaded state from {}", path.trim());
+                    continue;
                 }
+
+                self.input = line;
+                self.input.reverse();
             }
-            Op::Noop => {}
+            match self.input.pop() {
+                Some(c) => {
+                    self.set(a, c as u16)?;
+                    return Ok(State::Running);
+                }
+                None => return Ok(State::Halted),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn blocked_on_in() -> Vm {
+        // `in r0` followed by `hlt`, with pc already past the `in` the way
+        // `next_op` leaves it.
+        Vm {
+            bin: vec![20, 32768, 0],
+            pc: 2,
+            regs: vec![0; 8],
+            stack: Vec::new(),
+            input: Vec::new(),
+            cycles: 0,
+            max_cycles: None,
+            histogram: [0; 22],
+        }
+    }
+
+    // Regression test: saving while blocked on `in`, then loading that
+    // snapshot into a fresh Vm, must still let the player answer the
+    // resumed prompt instead of silently dropping it.
+    #[test]
+    fn load_resumes_a_pending_in() {
+        let path = std::env::temp_dir().join("synacor_test_pending_in.state");
+        let path = path.to_str().unwrap();
+
+        let mut saver = blocked_on_in();
+        let mut save_input = Cursor::new(format!("save {}\n", path).into_bytes());
+        assert!(saver.handle_in(32768, &mut save_input).is_ok());
+
+        let mut loader = blocked_on_in();
+        let mut load_input = Cursor::new(format!("load {}\nX\n", path).into_bytes());
+        let state = match loader.handle_in(32768, &mut load_input) {
+            Ok(state) => state,
+            Err(_) => panic!("handle_in should not trap"),
         };
-        State::Running
+
+        std::fs::remove_file(path).unwrap();
+
+        assert!(matches!(state, State::Running));
+        assert_eq!(loader.regs[0], b'X' as u16);
     }
 }